@@ -0,0 +1,1243 @@
+use alloc::boxed::Box;
+
+use collections::string::ToString;
+
+use core::cell::Cell;
+use core::{cmp, mem, ptr};
+
+use scheduler::context::Context;
+use common::debug;
+use common::event::{KeyEvent, MouseEvent};
+use common::memory;
+use common::time::{self, Duration};
+
+use graphics::display::VBEMODEINFO;
+
+use schemes::{KScheme, Resource, ResourceSeek};
+
+pub mod uhci;
+
+/// A USB control transfer's 8-byte setup stage, shared by every class driver
+/// and host controller backend.
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub len: u16,
+}
+
+const DESC_DEV: u8 = 1;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct DeviceDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    usb_version: u16,
+    class: u8,
+    sub_class: u8,
+    protocol: u8,
+    max_packet_size: u8,
+    vendor: u16,
+    product: u16,
+    release: u16,
+    manufacturer_string: u8,
+    product_string: u8,
+    serial_string: u8,
+    configurations: u8,
+}
+
+const DESC_CFG: u8 = 2;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct ConfigDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    total_length: u16,
+    interfaces: u8,
+    number: u8,
+    string: u8,
+    attributes: u8,
+    max_power: u8,
+}
+
+const DESC_INT: u8 = 4;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct InterfaceDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    number: u8,
+    alternate: u8,
+    endpoints: u8,
+    class: u8,
+    sub_class: u8,
+    protocol: u8,
+    string: u8,
+}
+
+const DESC_END: u8 = 5;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct EndpointDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    address: u8,
+    attributes: u8,
+    max_packet_size: u16,
+    interval: u8,
+}
+
+const DESC_HID: u8 = 0x21;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct HIDDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    hid_version: u16,
+    country_code: u8,
+    descriptors: u8,
+    sub_descriptor_type: u8,
+    sub_descriptor_length: u16,
+}
+
+// Usage IDs (keyboard page) for the modifier bits of a boot keyboard report,
+// in bit order: LeftCtrl, LeftShift, LeftAlt, LeftGUI, RightCtrl, RightShift,
+// RightAlt, RightGUI.
+const HID_MODIFIER_USAGE: [u8; 8] = [0xE0, 0xE1, 0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7];
+
+// Modifier-bit scancodes (PC AT Set 1 make codes), in the same bit order as
+// HID_MODIFIER_USAGE. RightShift has its own code (0x36); RightCtrl, RightAlt
+// and RightGUI reuse their left-hand code, since a single-byte scancode field
+// has no room for the 0xE0 prefix byte that sets them apart on real hardware.
+const HID_MODIFIER_SCANCODE: [u8; 8] = [0x1D, 0x2A, 0x38, 0x5B, 0x1D, 0x36, 0x38, 0x5B];
+
+// Translates a USB HID keyboard-page usage ID into a PC AT Set 1 make code,
+// the scancode representation the kernel's keyboard layer understands.
+// Usage IDs with no entry below are returned unchanged.
+fn hid_keycode_to_scancode(keycode: u8) -> u8 {
+    match keycode {
+        0x04 => 0x1E, // A
+        0x05 => 0x30, // B
+        0x06 => 0x2E, // C
+        0x07 => 0x20, // D
+        0x08 => 0x12, // E
+        0x09 => 0x21, // F
+        0x0A => 0x22, // G
+        0x0B => 0x23, // H
+        0x0C => 0x17, // I
+        0x0D => 0x24, // J
+        0x0E => 0x25, // K
+        0x0F => 0x26, // L
+        0x10 => 0x32, // M
+        0x11 => 0x31, // N
+        0x12 => 0x18, // O
+        0x13 => 0x19, // P
+        0x14 => 0x10, // Q
+        0x15 => 0x13, // R
+        0x16 => 0x1F, // S
+        0x17 => 0x14, // T
+        0x18 => 0x16, // U
+        0x19 => 0x2F, // V
+        0x1A => 0x11, // W
+        0x1B => 0x2D, // X
+        0x1C => 0x15, // Y
+        0x1D => 0x2C, // Z
+        0x1E => 0x02, // 1
+        0x1F => 0x03, // 2
+        0x20 => 0x04, // 3
+        0x21 => 0x05, // 4
+        0x22 => 0x06, // 5
+        0x23 => 0x07, // 6
+        0x24 => 0x08, // 7
+        0x25 => 0x09, // 8
+        0x26 => 0x0A, // 9
+        0x27 => 0x0B, // 0
+        0x28 => 0x1C, // Enter
+        0x29 => 0x01, // Escape
+        0x2A => 0x0E, // Backspace
+        0x2B => 0x0F, // Tab
+        0x2C => 0x39, // Space
+        0x2D => 0x0C, // - _
+        0x2E => 0x0D, // = +
+        0x2F => 0x1A, // [ {
+        0x30 => 0x1B, // ] }
+        0x31 => 0x2B, // \ |
+        0x33 => 0x27, // ; :
+        0x34 => 0x28, // ' "
+        0x35 => 0x29, // ` ~
+        0x36 => 0x33, // , <
+        0x37 => 0x34, // . >
+        0x38 => 0x35, // / ?
+        0x39 => 0x3A, // Caps Lock
+        0x3A => 0x3B, // F1
+        0x3B => 0x3C, // F2
+        0x3C => 0x3D, // F3
+        0x3D => 0x3E, // F4
+        0x3E => 0x3F, // F5
+        0x3F => 0x40, // F6
+        0x40 => 0x41, // F7
+        0x41 => 0x42, // F8
+        0x42 => 0x43, // F9
+        0x43 => 0x44, // F10
+        0x44 => 0x57, // F11
+        0x45 => 0x58, // F12
+        0x47 => 0x46, // Scroll Lock
+        0x49 => 0x52, // Insert
+        0x4A => 0x47, // Home
+        0x4B => 0x49, // Page Up
+        0x4C => 0x53, // Delete
+        0x4D => 0x4F, // End
+        0x4E => 0x51, // Page Down
+        0x4F => 0x4D, // Right Arrow
+        0x50 => 0x4B, // Left Arrow
+        0x51 => 0x50, // Down Arrow
+        0x52 => 0x48, // Up Arrow
+        _ => keycode,
+    }
+}
+
+fn hid_keycode_to_char(keycode: u8, shift: bool) -> char {
+    match keycode {
+        0x04...0x1D => {
+            let c = b'a' + (keycode - 0x04);
+            if shift {
+                (c - 0x20) as char
+            } else {
+                c as char
+            }
+        }
+        0x1E => if shift { '!' } else { '1' },
+        0x1F => if shift { '@' } else { '2' },
+        0x20 => if shift { '#' } else { '3' },
+        0x21 => if shift { '$' } else { '4' },
+        0x22 => if shift { '%' } else { '5' },
+        0x23 => if shift { '^' } else { '6' },
+        0x24 => if shift { '&' } else { '7' },
+        0x25 => if shift { '*' } else { '8' },
+        0x26 => if shift { '(' } else { '9' },
+        0x27 => if shift { ')' } else { '0' },
+        0x28 => '\n',
+        0x2A => '\u{8}',
+        0x2B => '\t',
+        0x2C => ' ',
+        0x2D => if shift { '_' } else { '-' },
+        0x2E => if shift { '+' } else { '=' },
+        0x2F => if shift { '{' } else { '[' },
+        0x30 => if shift { '}' } else { ']' },
+        0x31 => if shift { '|' } else { '\\' },
+        0x33 => if shift { ':' } else { ';' },
+        0x34 => if shift { '"' } else { '\'' },
+        0x35 => if shift { '~' } else { '`' },
+        0x36 => if shift { '<' } else { ',' },
+        0x37 => if shift { '>' } else { '.' },
+        0x38 => if shift { '?' } else { '/' },
+        _ => '\0',
+    }
+}
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CommandBlockWrapper {
+    signature: u32,
+    tag: u32,
+    data_transfer_length: u32,
+    flags: u8,
+    lun: u8,
+    cb_length: u8,
+    cb: [u8; 16],
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CommandStatusWrapper {
+    signature: u32,
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+/// A USB Mass Storage (Bulk-Only Transport) SCSI disk, driven through
+/// whichever `UsbHostController` enumerated it.
+pub struct MassStorageDisk {
+    controller: Box<UsbHostController>,
+    address: u8,
+    in_endpoint: u8,
+    in_max_packet: u16,
+    in_toggle: Cell<bool>,
+    out_endpoint: u8,
+    out_max_packet: u16,
+    out_toggle: Cell<bool>,
+    tag: Cell<u32>,
+    block_size: u32,
+    block_count: u64,
+}
+
+impl MassStorageDisk {
+    unsafe fn new(controller: Box<UsbHostController>,
+                  address: u8,
+                  in_endpoint: u8,
+                  in_max_packet: u16,
+                  out_endpoint: u8,
+                  out_max_packet: u16)
+                  -> Box<Self> {
+        let mut disk = box MassStorageDisk {
+            controller: controller,
+            address: address,
+            in_endpoint: in_endpoint,
+            in_max_packet: in_max_packet,
+            in_toggle: Cell::new(false),
+            out_endpoint: out_endpoint,
+            out_max_packet: out_max_packet,
+            out_toggle: Cell::new(false),
+            tag: Cell::new(1),
+            block_size: 512,
+            block_count: 0,
+        };
+
+        disk.inquiry();
+        disk.read_capacity();
+
+        disk
+    }
+
+    fn next_tag(&self) -> u32 {
+        let tag = self.tag.get();
+        self.tag.set(tag + 1);
+        tag
+    }
+
+    // Runs a single SCSI command through the Bulk-Only Transport: CBW on the
+    // bulk-OUT endpoint, an optional data phase, then the CSW on bulk-IN.
+    // A CSW whose signature or tag doesn't match the CBW we just sent means
+    // the device and host have lost BOT framing sync, so its status byte
+    // can't be trusted: treat that as a command failure rather than
+    // forwarding whatever garbage landed in `status`.
+    unsafe fn command(&self, cb: &[u8], data: *mut u8, data_len: u32, direction_in: bool) -> u8 {
+        let tag = self.next_tag();
+        let mut cbw = CommandBlockWrapper {
+            signature: CBW_SIGNATURE,
+            tag: tag,
+            data_transfer_length: data_len,
+            flags: if direction_in { 1 << 7 } else { 0 },
+            lun: 0,
+            cb_length: cb.len() as u8,
+            cb: [0; 16],
+        };
+        for (i, byte) in cb.iter().enumerate() {
+            cbw.cb[i] = *byte;
+        }
+
+        let cbw_buf: *mut CommandBlockWrapper = memory::alloc_type();
+        ptr::write(cbw_buf, cbw);
+        self.controller.bulk_transfer(self.address,
+                                       self.out_endpoint,
+                                       self.out_max_packet,
+                                       &self.out_toggle,
+                                       false,
+                                       cbw_buf as *mut u8,
+                                       mem::size_of::<CommandBlockWrapper>());
+        memory::unalloc(cbw_buf as usize);
+
+        if data_len > 0 {
+            let (endpoint, max_packet_size, toggle) = if direction_in {
+                (self.in_endpoint, self.in_max_packet, &self.in_toggle)
+            } else {
+                (self.out_endpoint, self.out_max_packet, &self.out_toggle)
+            };
+
+            self.controller.bulk_transfer(self.address,
+                                           endpoint,
+                                           max_packet_size,
+                                           toggle,
+                                           direction_in,
+                                           data,
+                                           data_len as usize);
+        }
+
+        let csw_buf: *mut CommandStatusWrapper = memory::alloc_type();
+        self.controller.bulk_transfer(self.address,
+                                       self.in_endpoint,
+                                       self.in_max_packet,
+                                       &self.in_toggle,
+                                       true,
+                                       csw_buf as *mut u8,
+                                       mem::size_of::<CommandStatusWrapper>());
+        let csw = ptr::read(csw_buf);
+        memory::unalloc(csw_buf as usize);
+
+        if csw.signature != CSW_SIGNATURE || csw.tag != tag {
+            debugln!("USB MSC CSW mismatch: signature {:X} tag {} (expected {})",
+                     csw.signature,
+                     csw.tag,
+                     tag);
+            0xFF
+        } else {
+            csw.status
+        }
+    }
+
+    unsafe fn inquiry(&self) {
+        let buf = memory::alloc(36) as *mut u8;
+        for i in 0..36 {
+            ptr::write(buf.offset(i), 0);
+        }
+
+        let cb = [0x12, 0, 0, 0, 36, 0];
+        let status = self.command(&cb, buf, 36, true);
+        debugln!("USB MSC INQUIRY status {}", status);
+
+        memory::unalloc(buf as usize);
+    }
+
+    unsafe fn read_capacity(&mut self) {
+        let buf = memory::alloc(8) as *mut u8;
+        for i in 0..8 {
+            ptr::write(buf.offset(i), 0);
+        }
+
+        let cb = [0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.command(&cb, buf, 8, true);
+
+        let last_lba = (ptr::read(buf.offset(0)) as u64) << 24 |
+                       (ptr::read(buf.offset(1)) as u64) << 16 |
+                       (ptr::read(buf.offset(2)) as u64) << 8 |
+                       (ptr::read(buf.offset(3)) as u64);
+        let block_size = (ptr::read(buf.offset(4)) as u32) << 24 |
+                          (ptr::read(buf.offset(5)) as u32) << 16 |
+                          (ptr::read(buf.offset(6)) as u32) << 8 |
+                          (ptr::read(buf.offset(7)) as u32);
+
+        self.block_count = last_lba + 1;
+        if block_size > 0 {
+            self.block_size = block_size;
+        }
+
+        debugln!("USB MSC block size {} count {}", self.block_size, self.block_count);
+
+        memory::unalloc(buf as usize);
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    // READ(10) always transfers whole blocks, so a `buffer` shorter than
+    // sectors * block_size gets its own bounce buffer sized to match: the
+    // BOT data phase and the SCSI data_transfer_length must agree on the
+    // byte count, or the device is told to send more than the TD chain
+    // covers.
+    pub unsafe fn read(&self, block: u64, buffer: &mut [u8]) -> u8 {
+        let sectors = (buffer.len() as u32 + self.block_size - 1) / self.block_size;
+        let transfer_len = sectors * self.block_size;
+        let cb = [0x28,
+                  0,
+                  (block >> 24) as u8,
+                  (block >> 16) as u8,
+                  (block >> 8) as u8,
+                  block as u8,
+                  0,
+                  (sectors >> 8) as u8,
+                  sectors as u8,
+                  0];
+
+        if transfer_len as usize == buffer.len() {
+            self.command(&cb, buffer.as_mut_ptr(), transfer_len, true)
+        } else {
+            let bounce = memory::alloc(transfer_len as usize) as *mut u8;
+            let status = self.command(&cb, bounce, transfer_len, true);
+            ptr::copy(bounce, buffer.as_mut_ptr(), buffer.len());
+            memory::unalloc(bounce as usize);
+            status
+        }
+    }
+
+    // See `read` above: WRITE(10) is likewise block-sized, so a short
+    // `buffer` is copied into a zero-padded bounce buffer of the full
+    // transfer length rather than under-reporting data_transfer_length.
+    pub unsafe fn write(&self, block: u64, buffer: &[u8]) -> u8 {
+        let sectors = (buffer.len() as u32 + self.block_size - 1) / self.block_size;
+        let transfer_len = sectors * self.block_size;
+        let cb = [0x2A,
+                  0,
+                  (block >> 24) as u8,
+                  (block >> 16) as u8,
+                  (block >> 8) as u8,
+                  block as u8,
+                  0,
+                  (sectors >> 8) as u8,
+                  sectors as u8,
+                  0];
+
+        if transfer_len as usize == buffer.len() {
+            self.command(&cb, buffer.as_ptr() as *mut u8, transfer_len, false)
+        } else {
+            let bounce = memory::alloc(transfer_len as usize) as *mut u8;
+            ptr::write_bytes(bounce, 0, transfer_len as usize);
+            ptr::copy(buffer.as_ptr(), bounce, buffer.len());
+            let status = self.command(&cb, bounce, transfer_len, false);
+            memory::unalloc(bounce as usize);
+            status
+        }
+    }
+}
+
+impl KScheme for MassStorageDisk {
+    fn scheme(&self) -> &str {
+        "usbmsc"
+    }
+
+    fn open(&mut self, _path: &str, _flags: usize) -> Option<Box<Resource>> {
+        Some(box MassStorageResource {
+            disk: self,
+            seek: Cell::new(0),
+        })
+    }
+
+    fn on_irq(&mut self, _irq: u8) {
+    }
+
+    fn on_poll(&mut self) {
+    }
+}
+
+// An open handle onto a MassStorageDisk: tracks a byte seek position and
+// translates it to the block number `MassStorageDisk::read`/`write` expect.
+// `disk` outlives every resource opened from it, since the disk is only
+// ever unregistered (and dropped) on shutdown.
+pub struct MassStorageResource {
+    disk: *mut MassStorageDisk,
+    seek: Cell<u64>,
+}
+
+impl Resource for MassStorageResource {
+    fn dup(&self) -> Option<Box<Resource>> {
+        Some(box MassStorageResource {
+            disk: self.disk,
+            seek: Cell::new(self.seek.get()),
+        })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Option<usize> {
+        let path = b"usbmsc:";
+        let len = cmp::min(buf.len(), path.len());
+        buf[..len].copy_from_slice(&path[..len]);
+        Some(len)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let disk = unsafe { &*self.disk };
+        let block = self.seek.get() / disk.block_size() as u64;
+        let status = unsafe { disk.read(block, buf) };
+        if status == 0 {
+            self.seek.set(self.seek.get() + buf.len() as u64);
+            Some(buf.len())
+        } else {
+            None
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        let disk = unsafe { &*self.disk };
+        let block = self.seek.get() / disk.block_size() as u64;
+        let status = unsafe { disk.write(block, buf) };
+        if status == 0 {
+            self.seek.set(self.seek.get() + buf.len() as u64);
+            Some(buf.len())
+        } else {
+            None
+        }
+    }
+
+    fn seek(&mut self, pos: ResourceSeek) -> Option<usize> {
+        let disk = unsafe { &*self.disk };
+        let size = disk.block_count() * disk.block_size() as u64;
+
+        let new_seek = match pos {
+            ResourceSeek::Start(offset) => offset as u64,
+            ResourceSeek::Current(offset) => (self.seek.get() as i64 + offset as i64) as u64,
+            ResourceSeek::End(offset) => (size as i64 + offset as i64) as u64,
+        };
+        self.seek.set(cmp::min(new_seek, size));
+
+        Some(self.seek.get() as usize)
+    }
+
+    fn sync(&mut self) -> Option<usize> {
+        Some(0)
+    }
+}
+
+const DESC_CS_INTERFACE: u8 = 0x24;
+
+const CDC_SUBTYPE_HEADER: u8 = 0x00;
+const CDC_SUBTYPE_UNION: u8 = 0x06;
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CdcHeaderDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    descriptor_subtype: u8,
+    cdc_version: u16,
+}
+
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct CdcUnionDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    descriptor_subtype: u8,
+    control_interface: u8,
+    subordinate_interface0: u8,
+}
+
+/// A CDC-ACM USB-serial port: the communication interface's notification
+/// endpoint is enumerated but not polled (nothing here needs the line state
+/// it reports), and I/O goes through the data interface's bulk IN/OUT
+/// endpoints via whichever `UsbHostController` enumerated it.
+pub struct UsbSerial {
+    controller: Box<UsbHostController>,
+    address: u8,
+    in_endpoint: u8,
+    in_max_packet: u16,
+    in_toggle: Cell<bool>,
+    out_endpoint: u8,
+    out_max_packet: u16,
+    out_toggle: Cell<bool>,
+}
+
+impl UsbSerial {
+    unsafe fn new(controller: Box<UsbHostController>,
+                  address: u8,
+                  in_endpoint: u8,
+                  in_max_packet: u16,
+                  out_endpoint: u8,
+                  out_max_packet: u16)
+                  -> Box<Self> {
+        box UsbSerial {
+            controller: controller,
+            address: address,
+            in_endpoint: in_endpoint,
+            in_max_packet: in_max_packet,
+            in_toggle: Cell::new(false),
+            out_endpoint: out_endpoint,
+            out_max_packet: out_max_packet,
+            out_toggle: Cell::new(false),
+        }
+    }
+
+    pub unsafe fn read(&self, buffer: &mut [u8]) -> usize {
+        self.controller.bulk_transfer(self.address,
+                                       self.in_endpoint,
+                                       self.in_max_packet,
+                                       &self.in_toggle,
+                                       true,
+                                       buffer.as_mut_ptr(),
+                                       buffer.len()) as usize
+    }
+
+    pub unsafe fn write(&self, buffer: &[u8]) -> usize {
+        self.controller.bulk_transfer(self.address,
+                                       self.out_endpoint,
+                                       self.out_max_packet,
+                                       &self.out_toggle,
+                                       false,
+                                       buffer.as_ptr() as *mut u8,
+                                       buffer.len()) as usize
+    }
+}
+
+impl KScheme for UsbSerial {
+    fn scheme(&self) -> &str {
+        "usbserial"
+    }
+
+    fn open(&mut self, _path: &str, _flags: usize) -> Option<Box<Resource>> {
+        Some(box UsbSerialResource { serial: self })
+    }
+
+    fn on_irq(&mut self, _irq: u8) {
+    }
+
+    fn on_poll(&mut self) {
+    }
+}
+
+// An open handle onto a UsbSerial: a character stream, so unlike
+// MassStorageResource it has no seek position to track.
+// `serial` outlives every resource opened from it, since the device is
+// only ever unregistered (and dropped) on shutdown.
+pub struct UsbSerialResource {
+    serial: *mut UsbSerial,
+}
+
+impl Resource for UsbSerialResource {
+    fn dup(&self) -> Option<Box<Resource>> {
+        Some(box UsbSerialResource { serial: self.serial })
+    }
+
+    fn path(&self, buf: &mut [u8]) -> Option<usize> {
+        let path = b"usbserial:";
+        let len = cmp::min(buf.len(), path.len());
+        buf[..len].copy_from_slice(&path[..len]);
+        Some(len)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        Some(unsafe { (*self.serial).read(buf) })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Option<usize> {
+        Some(unsafe { (*self.serial).write(buf) })
+    }
+
+    fn seek(&mut self, _pos: ResourceSeek) -> Option<usize> {
+        None
+    }
+
+    fn sync(&mut self) -> Option<usize> {
+        Some(0)
+    }
+}
+
+const DESC_HUB: u8 = 0x29;
+#[repr(packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct HubDescriptor {
+    length: u8,
+    descriptor_type: u8,
+    num_ports: u8,
+    characteristics: u16,
+    power_on_to_power_good: u8,
+    control_current: u8,
+}
+
+const HUB_FEATURE_PORT_RESET: u16 = 4;
+const HUB_FEATURE_PORT_POWER: u16 = 8;
+const HUB_FEATURE_C_PORT_CONNECTION: u16 = 16;
+const HUB_FEATURE_C_PORT_RESET: u16 = 20;
+
+// SET_FEATURE/CLEAR_FEATURE and GET_STATUS, class-specific and addressed at
+// a downstream port (request_type recipient bits = "other").
+unsafe fn hub_set_port_feature<C: UsbHostController>(controller: &C,
+                                                       address: u8,
+                                                       port: u8,
+                                                       feature: u16) {
+    controller.control_transfer(address,
+                                 Setup {
+                                     request_type: 0b00100011,
+                                     request: 3,
+                                     value: feature,
+                                     index: port as u16,
+                                     len: 0,
+                                 },
+                                 ptr::null_mut(),
+                                 false);
+}
+
+unsafe fn hub_clear_port_feature<C: UsbHostController>(controller: &C,
+                                                         address: u8,
+                                                         port: u8,
+                                                         feature: u16) {
+    controller.control_transfer(address,
+                                 Setup {
+                                     request_type: 0b00100011,
+                                     request: 1,
+                                     value: feature,
+                                     index: port as u16,
+                                     len: 0,
+                                 },
+                                 ptr::null_mut(),
+                                 false);
+}
+
+// Returns (status, change): the port status bits and their sticky
+// change-indicator bits, each as described in the USB 2.0 hub class spec.
+unsafe fn hub_port_status<C: UsbHostController>(controller: &C,
+                                                 address: u8,
+                                                 port: u8)
+                                                 -> (u16, u16) {
+    let buf: *mut u32 = memory::alloc_type();
+    ptr::write(buf, 0);
+    controller.control_transfer(address,
+                                 Setup {
+                                     request_type: 0b10100011,
+                                     request: 0,
+                                     value: 0,
+                                     index: port as u16,
+                                     len: 4,
+                                 },
+                                 buf as *mut u8,
+                                 true);
+    let value = ptr::read(buf);
+    memory::unalloc(buf as usize);
+    (value as u16, (value >> 16) as u16)
+}
+
+/// Everything a USB class driver or the enumeration routine below needs from
+/// a host controller, independent of whether it is backed by UHCI's
+/// Td/Qh/frame-list scheme, OHCI's ED/TD scheme, or anything else.
+///
+/// `control_transfer` covers all three control transfer stages (setup,
+/// optional data, status): `setup.len == 0` means no data stage, otherwise
+/// `direction_in` selects whether the data stage is IN or OUT. It returns the
+/// number of bytes actually transferred in the data stage. `bulk_transfer`
+/// likewise returns the number of bytes actually transferred, which can be
+/// less than `len` on a short read.
+pub trait UsbHostController {
+    unsafe fn control_transfer(&self,
+                                address: u8,
+                                setup: Setup,
+                                buffer: *mut u8,
+                                direction_in: bool)
+                                -> u32;
+
+    unsafe fn interrupt_transfer(&self,
+                                  address: u8,
+                                  endpoint: u8,
+                                  max_packet_size: u16,
+                                  buffer: *mut u8,
+                                  len: usize)
+                                  -> u32;
+
+    unsafe fn bulk_transfer(&self,
+                             address: u8,
+                             endpoint: u8,
+                             max_packet_size: u16,
+                             toggle: &Cell<bool>,
+                             direction_in: bool,
+                             buffer: *mut u8,
+                             len: usize)
+                             -> u32;
+}
+
+/// Assigns an address (drawn from `next_address`, shared by every root port
+/// of one host controller and every hub below them, so addresses on that
+/// bus never collide), walks the
+/// device and configuration descriptors, and dispatches to the appropriate
+/// class driver, all through `controller`. Shared by every host controller
+/// backend so none of them need to reimplement enumeration, and called
+/// recursively by the hub driver below for each downstream port.
+pub unsafe fn enumerate<C: UsbHostController + Clone + 'static>(controller: &C,
+                                                                 next_address: *mut Cell<u8>) {
+    let address = (*next_address).get();
+    (*next_address).set(address + 1);
+
+    controller.control_transfer(0,
+                                 Setup {
+                                     request_type: 0b00000000,
+                                     request: 5,
+                                     value: address as u16,
+                                     index: 0,
+                                     len: 0,
+                                 },
+                                 ptr::null_mut(),
+                                 false);
+
+    let desc_dev: *mut DeviceDescriptor = memory::alloc_type();
+    ptr::write(desc_dev, DeviceDescriptor::default());
+    controller.control_transfer(address,
+                                 Setup {
+                                     request_type: 0b10000000,
+                                     request: 6,
+                                     value: (DESC_DEV as u16) << 8,
+                                     index: 0,
+                                     len: mem::size_of_val(&*desc_dev) as u16,
+                                 },
+                                 desc_dev as *mut u8,
+                                 true);
+    debugln!("{:#?}", *desc_dev);
+
+    for configuration in 0..(*desc_dev).configurations {
+        let desc_cfg_len = 1023;
+        let desc_cfg_buf = memory::alloc(desc_cfg_len) as *mut u8;
+        for i in 0..desc_cfg_len as isize {
+            ptr::write(desc_cfg_buf.offset(i), 0);
+        }
+        controller.control_transfer(address,
+                                     Setup {
+                                         request_type: 0b10000000,
+                                         request: 6,
+                                         value: (DESC_CFG as u16) << 8 | configuration as u16,
+                                         index: 0,
+                                         len: desc_cfg_len as u16,
+                                     },
+                                     desc_cfg_buf,
+                                     true);
+
+        let desc_cfg = ptr::read(desc_cfg_buf as *const ConfigDescriptor);
+        debugln!("{:#?}", desc_cfg);
+
+        let mut hid = false;
+        let mut interface_number = 0;
+        let mut interface_class = 0;
+        let mut interface_sub_class = 0;
+        let mut interface_protocol = 0;
+        let mut storage_in: Option<(u8, u16)> = None;
+        let mut storage_out: Option<(u8, u16)> = None;
+        // Unlike storage_in/storage_out, this survives the reset at each new
+        // DESC_INT: the CDC-ACM notification endpoint lives on the
+        // communication interface but the bulk data endpoints it pairs with
+        // are on the data interface that follows it.
+        let mut serial_notify: Option<(u8, u16)> = None;
+        let mut serial_in: Option<(u8, u16)> = None;
+        let mut serial_out: Option<(u8, u16)> = None;
+
+        let mut i = desc_cfg.length as isize;
+        while i < desc_cfg.total_length as isize {
+            let length = ptr::read(desc_cfg_buf.offset(i));
+            let descriptor_type = ptr::read(desc_cfg_buf.offset(i + 1));
+            match descriptor_type {
+                DESC_INT => {
+                    let desc_int = ptr::read(desc_cfg_buf.offset(i) as *const InterfaceDescriptor);
+                    debugln!("{:#?}", desc_int);
+
+                    hid = false;
+                    interface_number = desc_int.number;
+                    interface_class = desc_int.class;
+                    interface_sub_class = desc_int.sub_class;
+                    interface_protocol = desc_int.protocol;
+                    storage_in = None;
+                    storage_out = None;
+                    serial_in = None;
+                    serial_out = None;
+                }
+                DESC_END => {
+                    let desc_end = ptr::read(desc_cfg_buf.offset(i) as *const EndpointDescriptor);
+                    debugln!("{:#?}", desc_end);
+
+                    let endpoint = desc_end.address & 0xF;
+                    let in_len = desc_end.max_packet_size as usize;
+
+                    if hid && interface_class == 3 && interface_sub_class == 1 &&
+                       interface_protocol == 1 {
+                        controller.control_transfer(address,
+                                                     Setup {
+                                                         request_type: 0b00100001,
+                                                         request: 0x0B,
+                                                         value: 0,
+                                                         index: interface_number as u16,
+                                                         len: 0,
+                                                     },
+                                                     ptr::null_mut(),
+                                                     false);
+
+                        let controller = controller.clone();
+                        Context::spawn("kuhci_keyboard".to_string(), box move || {
+                            debugln!("Starting keyboard driver");
+
+                            let in_ptr = memory::alloc(in_len) as *mut u8;
+
+                            let mut prev_modifier: u8 = 0;
+                            let mut prev_keys = [0u8; 6];
+
+                            loop {
+                                for i in 0..in_len as isize {
+                                    ptr::write(in_ptr.offset(i), 0);
+                                }
+
+                                let actual = controller.interrupt_transfer(address,
+                                                                            endpoint,
+                                                                            in_len as u16,
+                                                                            in_ptr,
+                                                                            in_len);
+
+                                if actual > 0 {
+                                    let modifier = ptr::read(in_ptr.offset(0) as *const u8);
+
+                                    let mut keys = [0u8; 6];
+                                    for k in 0..6 {
+                                        keys[k] = ptr::read(in_ptr.offset(2 + k as isize) as *const u8);
+                                    }
+
+                                    let shift = modifier & (1 << 1) != 0 || modifier & (1 << 5) != 0;
+
+                                    for bit in 0..HID_MODIFIER_USAGE.len() {
+                                        let was_down = prev_modifier & (1 << bit) != 0;
+                                        let is_down = modifier & (1 << bit) != 0;
+                                        if is_down != was_down {
+                                            let key_event = KeyEvent {
+                                                character: '\0',
+                                                scancode: HID_MODIFIER_SCANCODE[bit],
+                                                pressed: is_down,
+                                            };
+                                            ::env().events.lock().push_back(key_event.to_event());
+                                        }
+                                    }
+
+                                    for &keycode in prev_keys.iter() {
+                                        if keycode > 1 && !keys.contains(&keycode) {
+                                            let key_event = KeyEvent {
+                                                character: hid_keycode_to_char(keycode, shift),
+                                                scancode: hid_keycode_to_scancode(keycode),
+                                                pressed: false,
+                                            };
+                                            ::env().events.lock().push_back(key_event.to_event());
+                                        }
+                                    }
+
+                                    for &keycode in keys.iter() {
+                                        if keycode > 1 && !prev_keys.contains(&keycode) {
+                                            let key_event = KeyEvent {
+                                                character: hid_keycode_to_char(keycode, shift),
+                                                scancode: hid_keycode_to_scancode(keycode),
+                                                pressed: true,
+                                            };
+                                            ::env().events.lock().push_back(key_event.to_event());
+                                        }
+                                    }
+
+                                    prev_modifier = modifier;
+                                    prev_keys = keys;
+                                }
+
+                                Duration::new(0, 10 * time::NANOS_PER_MILLI).sleep();
+                            }
+                        });
+                    } else if hid && interface_class == 3 && interface_sub_class == 1 &&
+                              interface_protocol == 2 {
+                        controller.control_transfer(address,
+                                                     Setup {
+                                                         request_type: 0b00100001,
+                                                         request: 0x0B,
+                                                         value: 0,
+                                                         index: interface_number as u16,
+                                                         len: 0,
+                                                     },
+                                                     ptr::null_mut(),
+                                                     false);
+
+                        let controller = controller.clone();
+                        Context::spawn("kuhci_hid".to_string(), box move || {
+                            debugln!("Starting HID driver");
+
+                            let in_ptr = memory::alloc(in_len) as *mut u8;
+
+                            loop {
+                                for i in 0..in_len as isize {
+                                    ptr::write(in_ptr.offset(i), 0);
+                                }
+
+                                let actual = controller.interrupt_transfer(address,
+                                                                            endpoint,
+                                                                            in_len as u16,
+                                                                            in_ptr,
+                                                                            in_len);
+
+                                if actual > 0 {
+                                    let buttons = ptr::read(in_ptr.offset(0) as *const u8) as usize;
+                                    let x = ptr::read(in_ptr.offset(1) as *const u16) as usize;
+                                    let y = ptr::read(in_ptr.offset(3) as *const u16) as usize;
+
+                                    let mode_info = &*VBEMODEINFO;
+                                    let mouse_x = (x * mode_info.xresolution as usize) / 32768;
+                                    let mouse_y = (y * mode_info.yresolution as usize) / 32768;
+
+                                    let mouse_event = MouseEvent {
+                                        x: cmp::max(0,
+                                                     cmp::min(mode_info.xresolution as i32 - 1,
+                                                              mouse_x as i32)),
+                                        y: cmp::max(0,
+                                                     cmp::min(mode_info.yresolution as i32 - 1,
+                                                              mouse_y as i32)),
+                                        left_button: buttons & 1 == 1,
+                                        middle_button: buttons & 4 == 4,
+                                        right_button: buttons & 2 == 2,
+                                    };
+                                    ::env().events.lock().push_back(mouse_event.to_event());
+                                }
+
+                                Duration::new(0, 10 * time::NANOS_PER_MILLI).sleep();
+                            }
+                        });
+                    }
+
+                    if interface_class == 8 && interface_sub_class == 6 &&
+                       interface_protocol == 0x50 {
+                        if desc_end.address & 0x80 == 0x80 {
+                            storage_in = Some((endpoint, desc_end.max_packet_size));
+                        } else {
+                            storage_out = Some((endpoint, desc_end.max_packet_size));
+                        }
+
+                        if let (Some((in_endpoint, in_max_packet)),
+                                Some((out_endpoint, out_max_packet))) = (storage_in, storage_out) {
+                            let disk = MassStorageDisk::new(box controller.clone(),
+                                                            address,
+                                                            in_endpoint,
+                                                            in_max_packet,
+                                                            out_endpoint,
+                                                            out_max_packet);
+                            ::env().schemes.lock().push(disk);
+                        }
+                    }
+
+                    if interface_class == 9 {
+                        let desc_hub_len = 16;
+                        let desc_hub_buf = memory::alloc(desc_hub_len) as *mut u8;
+                        for j in 0..desc_hub_len as isize {
+                            ptr::write(desc_hub_buf.offset(j), 0);
+                        }
+                        controller.control_transfer(address,
+                                                     Setup {
+                                                         request_type: 0b10100000,
+                                                         request: 6,
+                                                         value: (DESC_HUB as u16) << 8,
+                                                         index: 0,
+                                                         len: desc_hub_len as u16,
+                                                     },
+                                                     desc_hub_buf,
+                                                     true);
+                        let desc_hub = ptr::read(desc_hub_buf as *const HubDescriptor);
+                        debugln!("{:#?}", desc_hub);
+                        memory::unalloc(desc_hub_buf as usize);
+
+                        for port in 1..(desc_hub.num_ports + 1) {
+                            hub_set_port_feature(controller, address, port, HUB_FEATURE_PORT_POWER);
+                        }
+                        Duration::new(0,
+                                      desc_hub.power_on_to_power_good as u32 * 2 *
+                                      time::NANOS_PER_MILLI)
+                            .sleep();
+
+                        let num_ports = desc_hub.num_ports;
+                        let controller = controller.clone();
+                        Context::spawn("kusb_hub".to_string(), box move || {
+                            debugln!("Starting hub driver");
+
+                            let in_ptr = memory::alloc(in_len) as *mut u8;
+
+                            loop {
+                                for i in 0..in_len as isize {
+                                    ptr::write(in_ptr.offset(i), 0);
+                                }
+
+                                let actual = controller.interrupt_transfer(address,
+                                                                            endpoint,
+                                                                            in_len as u16,
+                                                                            in_ptr,
+                                                                            in_len);
+
+                                if actual > 0 {
+                                    let change = ptr::read(in_ptr as *const u8);
+
+                                    for port in 1..(num_ports + 1) {
+                                        if change & (1 << port) == 0 {
+                                            continue;
+                                        }
+
+                                        let (status, port_change) =
+                                            hub_port_status(&controller, address, port);
+
+                                        if port_change & 1 != 0 {
+                                            hub_clear_port_feature(&controller,
+                                                                    address,
+                                                                    port,
+                                                                    HUB_FEATURE_C_PORT_CONNECTION);
+
+                                            if status & 1 != 0 {
+                                                hub_set_port_feature(&controller,
+                                                                      address,
+                                                                      port,
+                                                                      HUB_FEATURE_PORT_RESET);
+
+                                                loop {
+                                                    let (_, reset_change) =
+                                                        hub_port_status(&controller, address, port);
+                                                    if reset_change & (1 << 4) != 0 {
+                                                        break;
+                                                    }
+                                                    Duration::new(0, 10 * time::NANOS_PER_MILLI)
+                                                        .sleep();
+                                                }
+                                                hub_clear_port_feature(&controller,
+                                                                        address,
+                                                                        port,
+                                                                        HUB_FEATURE_C_PORT_RESET);
+
+                                                enumerate(&controller, next_address);
+                                            }
+                                        }
+                                    }
+                                }
+
+                                Duration::new(0, 10 * time::NANOS_PER_MILLI).sleep();
+                            }
+                        });
+                    }
+
+                    if interface_class == 2 {
+                        serial_notify = Some((endpoint, desc_end.max_packet_size));
+                    }
+
+                    if interface_class == 0x0A {
+                        if desc_end.address & 0x80 == 0x80 {
+                            serial_in = Some((endpoint, desc_end.max_packet_size));
+                        } else {
+                            serial_out = Some((endpoint, desc_end.max_packet_size));
+                        }
+
+                        if let (Some(_), Some((in_endpoint, in_max_packet)),
+                                Some((out_endpoint, out_max_packet))) =
+                               (serial_notify, serial_in, serial_out) {
+                            let serial = UsbSerial::new(box controller.clone(),
+                                                        address,
+                                                        in_endpoint,
+                                                        in_max_packet,
+                                                        out_endpoint,
+                                                        out_max_packet);
+                            ::env().schemes.lock().push(serial);
+                        }
+                    }
+                }
+                DESC_HID => {
+                    let desc_hid = &*(desc_cfg_buf.offset(i) as *const HIDDescriptor);
+                    debugln!("{:#?}", desc_hid);
+                    hid = true;
+                }
+                DESC_CS_INTERFACE => {
+                    let descriptor_subtype = ptr::read(desc_cfg_buf.offset(i + 2));
+                    match descriptor_subtype {
+                        CDC_SUBTYPE_HEADER => {
+                            let desc_header = ptr::read(desc_cfg_buf.offset(i) as
+                                                         *const CdcHeaderDescriptor);
+                            debugln!("{:#?}", desc_header);
+                        }
+                        CDC_SUBTYPE_UNION => {
+                            let desc_union = ptr::read(desc_cfg_buf.offset(i) as
+                                                        *const CdcUnionDescriptor);
+                            debugln!("{:#?}", desc_union);
+                        }
+                        _ => {
+                            debug::d("CDC Functional Descriptor Subtype ");
+                            debug::dh(descriptor_subtype as usize);
+                            debug::dl();
+                        }
+                    }
+                }
+                _ => {
+                    debug::d("Unknown Descriptor Length ");
+                    debug::dd(length as usize);
+                    debug::d(" Type ");
+                    debug::dh(descriptor_type as usize);
+                    debug::dl();
+                }
+            }
+            i += length as isize;
+        }
+
+        memory::unalloc(desc_cfg_buf as usize);
+    }
+
+    memory::unalloc(desc_dev as usize);
+}
@@ -1,34 +1,145 @@
 use alloc::boxed::Box;
 
-use collections::string::ToString;
+use collections::vec::Vec;
 
+use core::cell::Cell;
 use core::intrinsics::{volatile_load, volatile_store};
 use core::{cmp, mem, ptr};
 
-use scheduler::context::{self, Context};
+use scheduler::context;
 use common::debug;
-use common::event::MouseEvent;
 use common::memory::{self, Memory};
-use common::time::{self, Duration};
 
 use drivers::pciconfig::PciConfig;
 use drivers::pio::*;
 
-use graphics::display::VBEMODEINFO;
-
 use schemes::KScheme;
 
 use sync::Intex;
 
+use super::{enumerate, Setup, UsbHostController};
+
+// A per-transfer queue head linked horizontally off the controller's
+// persistent async_qh and awaiting completion. `last_td` is the physical
+// address of the final TD in its chain: once its active bit (ctrl_sts bit
+// 23) clears, the whole chain is done and `done` is set so the submitter
+// can stop waiting.
+struct UhciTransfer {
+    qh: u32,
+    last_td: u32,
+    done: *mut bool,
+}
+
+// Rebuilds the horizontal QH chain starting at async_qh so it links every
+// entry in `transfers`, in order, terminating after the last one. Every
+// frame_list slot points at async_qh permanently (set up once in
+// Uhci::new), so this is the only place transfers are ever linked into or
+// out of the schedule -- callers never touch frame_list themselves, which
+// is what lets an arbitrary number of transfers be in flight at once
+// without two of them fighting over the same slot. Must be called with
+// `transfers` already updated and under the same lock that protects it.
+unsafe fn relink_schedule(async_qh: *mut Qh, transfers: &[UhciTransfer]) {
+    let mut qh = ptr::read(async_qh);
+    qh.head_ptr = transfers.first().map_or(1, |t| t.qh | 2);
+    ptr::write(async_qh, qh);
+
+    for (i, transfer) in transfers.iter().enumerate() {
+        let mut qh = ptr::read(transfer.qh as *const Qh);
+        qh.head_ptr = transfers.get(i + 1).map_or(1, |t| t.qh | 2);
+        ptr::write(transfer.qh as *mut Qh, qh);
+    }
+}
+
+// Splices this transfer's queue head into the controller's persistent
+// async schedule and queues `last_td` for on_irq to watch, both under the
+// same lock so the TD can never complete (and raise its IOC IRQ) in the
+// window between linking it and on_irq being able to see it: on_irq cannot
+// run between the two since it takes the same lock. Blocks the calling
+// context until on_irq reports completion, or until it observes the TD's
+// active bit clear itself, in case on_irq ran (and found nothing to do,
+// e.g. for an unrelated IRQ) before this context next got to check.
+unsafe fn wait_for_transfer(transfers: *mut Vec<UhciTransfer>,
+                             async_qh: *mut Qh,
+                             qh: u32,
+                             last_td: *mut Td) {
+    let done: *mut bool = memory::alloc_type();
+    ptr::write(done, false);
+
+    {
+        let _intex = Intex::static_lock();
+        let transfers = &mut *transfers;
+        transfers.push(UhciTransfer {
+            qh: qh,
+            last_td: last_td as u32,
+            done: done,
+        });
+        relink_schedule(async_qh, transfers);
+    }
+
+    while !volatile_load(done) {
+        if volatile_load(last_td).ctrl_sts & (1 << 23) == 0 {
+            let _intex = Intex::static_lock();
+            if !volatile_load(done) {
+                let transfers = &mut *transfers;
+                if let Some(pos) = transfers.iter().position(|t| t.last_td == last_td as u32) {
+                    transfers.remove(pos);
+                    relink_schedule(async_qh, transfers);
+                }
+                volatile_store(done, true);
+            }
+        } else {
+            context::context_switch(false);
+        }
+    }
+
+    memory::unalloc(done as usize);
+}
+
+#[derive(Clone)]
 pub struct Uhci {
     pub base: usize,
     pub irq: u8,
+    frame_list: *mut u32,
+    async_qh: *mut Qh,
+    transfers: *mut Vec<UhciTransfer>,
 }
 
 impl KScheme for Uhci {
     fn on_irq(&mut self, irq: u8) {
         if irq == self.irq {
-            // d("UHCI IRQ\n");
+            let base = self.base as u16;
+            let usbsts = base + 2;
+
+            unsafe {
+                let sts = inw(usbsts);
+                if sts & 0x3 != 0 {
+                    outw(usbsts, sts & 0x3);
+
+                    let _intex = Intex::static_lock();
+                    let transfers = &mut *self.transfers;
+
+                    let mut i = 0;
+                    let mut removed = false;
+                    while i < transfers.len() {
+                        let done = {
+                            let td = transfers[i].last_td as *const Td;
+                            volatile_load(td).ctrl_sts & (1 << 23) == 0
+                        };
+
+                        if done {
+                            volatile_store(transfers[i].done, true);
+                            transfers.remove(i);
+                            removed = true;
+                        } else {
+                            i += 1;
+                        }
+                    }
+
+                    if removed {
+                        relink_schedule(self.async_qh, transfers);
+                    }
+                }
+            }
         }
     }
 
@@ -36,16 +147,6 @@ impl KScheme for Uhci {
     }
 }
 
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct Setup {
-    request_type: u8,
-    request: u8,
-    value: u16,
-    index: u16,
-    len: u16,
-}
-
 #[repr(packed)]
 #[derive(Copy, Clone, Debug, Default)]
 struct Td {
@@ -62,124 +163,57 @@ struct Qh {
     element_ptr: u32,
 }
 
-const DESC_DEV: u8 = 1;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct DeviceDescriptor {
-    length: u8,
-    descriptor_type: u8,
-    usb_version: u16,
-    class: u8,
-    sub_class: u8,
-    protocol: u8,
-    max_packet_size: u8,
-    vendor: u16,
-    product: u16,
-    release: u16,
-    manufacturer_string: u8,
-    product_string: u8,
-    serial_string: u8,
-    configurations: u8,
-}
-
-const DESC_CFG: u8 = 2;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct ConfigDescriptor {
-    length: u8,
-    descriptor_type: u8,
-    total_length: u16,
-    interfaces: u8,
-    number: u8,
-    string: u8,
-    attributes: u8,
-    max_power: u8,
-}
-
-const DESC_INT: u8 = 4;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct InterfaceDescriptor {
-    length: u8,
-    descriptor_type: u8,
-    number: u8,
-    alternate: u8,
-    endpoints: u8,
-    class: u8,
-    sub_class: u8,
-    protocol: u8,
-    string: u8,
-}
-
-const DESC_END: u8 = 5;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct EndpointDescriptor {
-    length: u8,
-    descriptor_type: u8,
-    address: u8,
-    attributes: u8,
-    max_packet_size: u16,
-    interval: u8,
-}
-
-const DESC_HID: u8 = 0x21;
-#[repr(packed)]
-#[derive(Copy, Clone, Debug, Default)]
-struct HIDDescriptor {
-    length: u8,
-    descriptor_type: u8,
-    hid_version: u16,
-    country_code: u8,
-    descriptors: u8,
-    sub_descriptor_type: u8,
-    sub_descriptor_length: u16,
-}
-
-impl Uhci {
-    pub unsafe fn new(mut pci: PciConfig) -> Box<Self> {
-        pci.flag(4, 4, true); // Bus mastering
-
-        let module = box Uhci {
-            base: pci.read(0x20) as usize & 0xFFFFFFF0,
-            irq: pci.read(0x3C) as u8 & 0xF,
+impl UsbHostController for Uhci {
+    // Builds a setup TD, an optional data-stage TD, and a status TD (the
+    // direction opposite the data stage, or IN when there is no data
+    // stage), chains them into one queue head, and waits on the last one.
+    unsafe fn control_transfer(&self,
+                                address: u8,
+                                setup: Setup,
+                                buffer: *mut u8,
+                                direction_in: bool)
+                                -> u32 {
+        let mut setup_mem = Memory::<Setup>::new(1).unwrap();
+        setup_mem.store(0, setup);
+
+        let mut status_td = Memory::<Td>::new(1).unwrap();
+        let status_pid = if setup.len > 0 && direction_in {
+            0xE1
+        } else {
+            0x69
+        };
+        status_td.store(0,
+                        Td {
+                            link_ptr: 1,
+                            ctrl_sts: 1 << 24 | 1 << 23,
+                            token: 0x7FF << 21 | (address as u32) << 8 | status_pid,
+                            buffer: 0,
+                        });
+
+        let mut data_td = Memory::<Td>::new(1).unwrap();
+        let first_td_addr = if setup.len > 0 {
+            let data_pid = if direction_in { 0x69 } else { 0xE1 };
+            data_td.store(0,
+                         Td {
+                             link_ptr: status_td.address() as u32 | 4,
+                             ctrl_sts: 1 << 24 | 1 << 23,
+                             token: (setup.len as u32 - 1) << 21 | (address as u32) << 8 |
+                                    data_pid,
+                             buffer: buffer as u32,
+                         });
+            data_td.address()
+        } else {
+            status_td.address()
         };
-
-        module.init();
-
-        return module;
-    }
-
-    unsafe fn set_address(&self, frame_list: *mut u32, address: u8) {
-        let base = self.base as u16;
-        let frnum = Pio16::new(base + 6);
-
-        let mut in_td = Memory::<Td>::new(1).unwrap();
-        in_td.store(0,
-                    Td {
-                        link_ptr: 1,
-                        ctrl_sts: 1 << 23,
-                        token: 0x7FF << 21 | 0x69,
-                        buffer: 0,
-                    });
-
-        let mut setup = Memory::<Setup>::new(1).unwrap();
-        setup.store(0,
-                    Setup {
-                        request_type: 0b00000000,
-                        request: 5,
-                        value: address as u16,
-                        index: 0,
-                        len: 0,
-                    });
 
         let mut setup_td = Memory::<Td>::new(1).unwrap();
         setup_td.store(0,
                        Td {
-                           link_ptr: in_td.address() as u32 | 4,
-                           ctrl_sts: 1 << 23,
-                           token: (mem::size_of::<Setup>() as u32 - 1) << 21 | 0x2D,
-                           buffer: setup.address() as u32,
+                           link_ptr: first_td_addr as u32 | 4,
+                           ctrl_sts: 1 << 24 | 1 << 23,
+                           token: (mem::size_of::<Setup>() as u32 - 1) << 21 |
+                                  (address as u32) << 8 | 0x2D,
+                           buffer: setup_mem.address() as u32,
                        });
 
         let mut queue_head = Memory::<Qh>::new(1).unwrap();
@@ -189,245 +223,155 @@ impl Uhci {
                              element_ptr: setup_td.address() as u32,
                          });
 
-        let frame = (frnum.read() + 2) & 0x3FF;
-        ptr::write(frame_list.offset(frame as isize),
-                   queue_head.address() as u32 | 2);
-
-        loop {
-            if setup_td.load(0).ctrl_sts & (1 << 23) == 0 {
-                break;
-            }
-        }
+        wait_for_transfer(self.transfers,
+                          self.async_qh,
+                          queue_head.address() as u32,
+                          status_td.address() as *mut Td);
 
-        loop {
-            if in_td.load(0).ctrl_sts & (1 << 23) == 0 {
-                break;
-            }
+        if setup.len > 0 {
+            // ActLen (ctrl_sts bits 0-10) is encoded as n-1, with 0x7FF for zero.
+            ((volatile_load(data_td.address() as *const Td).ctrl_sts & 0x7FF) + 1) & 0x7FF
+        } else {
+            0
         }
-
-        ptr::write(frame_list.offset(frame as isize), 1);
     }
 
-    unsafe fn descriptor(&self,
-                         frame_list: *mut u32,
-                         address: u8,
-                         descriptor_type: u8,
-                         descriptor_index: u8,
-                         descriptor_ptr: u32,
-                         descriptor_len: u32) {
-        let base = self.base as u16;
-        let frnum = Pio16::new(base + 6);
-
-        let mut out_td = Memory::<Td>::new(1).unwrap();
-        out_td.store(0,
-                     Td {
-                         link_ptr: 1,
-                         ctrl_sts: 1 << 23,
-                         token: 0x7FF << 21 | (address as u32) << 8 | 0xE1,
-                         buffer: 0,
-                     });
-
-        let mut in_td = Memory::<Td>::new(1).unwrap();
-        in_td.store(0,
-                    Td {
-                        link_ptr: out_td.address() as u32 | 4,
-                        ctrl_sts: 1 << 23,
-                        token: (descriptor_len - 1) << 21 | (address as u32) << 8 | 0x69,
-                        buffer: descriptor_ptr,
-                    });
-
-        let mut setup = Memory::<Setup>::new(1).unwrap();
-        setup.store(0,
-                    Setup {
-                        request_type: 0b10000000,
-                        request: 6,
-                        value: (descriptor_type as u16) << 8 | (descriptor_index as u16),
-                        index: 0,
-                        len: descriptor_len as u16,
-                    });
-
-        let mut setup_td = Memory::<Td>::new(1).unwrap();
-        setup_td.store(0,
-                       Td {
-                           link_ptr: in_td.address() as u32 | 4,
-                           ctrl_sts: 1 << 23,
-                           token: (mem::size_of::<Setup>() as u32 - 1) << 21 |
-                                  (address as u32) << 8 | 0x2D,
-                           buffer: setup.address() as u32,
-                       });
+    // Submits a single interrupt-pipe IN transfer and waits for it on_irq,
+    // mirroring how control_transfer and bulk_transfer queue their TDs.
+    unsafe fn interrupt_transfer(&self,
+                                  address: u8,
+                                  endpoint: u8,
+                                  max_packet_size: u16,
+                                  buffer: *mut u8,
+                                  len: usize)
+                                  -> u32 {
+        let in_td: *mut Td = memory::alloc_type();
+        ptr::write(in_td,
+                   Td {
+                       link_ptr: 1,
+                       ctrl_sts: 1 << 25 | 1 << 24 | 1 << 23,
+                       token: (cmp::min(len, max_packet_size as usize) as u32 - 1) << 21 |
+                              (endpoint as u32) << 15 |
+                              (address as u32) << 8 | 0x69,
+                       buffer: buffer as u32,
+                   });
 
         let mut queue_head = Memory::<Qh>::new(1).unwrap();
         queue_head.store(0,
                          Qh {
                              head_ptr: 1,
-                             element_ptr: setup_td.address() as u32,
+                             element_ptr: in_td as u32,
                          });
 
-        let frame = (frnum.read() + 2) & 0x3FF;
-        ptr::write(frame_list.offset(frame as isize),
-                   queue_head.address() as u32 | 2);
+        wait_for_transfer(self.transfers, self.async_qh, queue_head.address() as u32, in_td);
 
-        loop {
-            if setup_td.load(0).ctrl_sts & (1 << 23) == 0 {
-                break;
-            }
-        }
+        let actual = ((volatile_load(in_td).ctrl_sts & 0x7FF) + 1) & 0x7FF;
+        memory::unalloc(in_td as usize);
+        actual
+    }
 
-        loop {
-            if in_td.load(0).ctrl_sts & (1 << 23) == 0 {
-                break;
-            }
+    // Splits a bulk transfer across as many max_packet_size TDs as required,
+    // chaining them into a single queue head and toggling the data toggle bit
+    // (token bit 19) on each one, mirroring how control transfers above
+    // chain setup/data/status TDs.
+    unsafe fn bulk_transfer(&self,
+                             address: u8,
+                             endpoint: u8,
+                             max_packet_size: u16,
+                             toggle: &Cell<bool>,
+                             direction_in: bool,
+                             buffer: *mut u8,
+                             len: usize)
+                             -> u32 {
+        let max_packet_size = cmp::max(max_packet_size as usize, 1);
+        let td_count = cmp::max((len + max_packet_size - 1) / max_packet_size, 1);
+
+        let mut tds = Memory::<Td>::new(td_count).unwrap();
+
+        let pid = if direction_in { 0x69 } else { 0xE1 };
+
+        let mut offset = 0;
+        for i in 0..td_count {
+            let packet_len = cmp::min(max_packet_size, len - offset);
+
+            let link_ptr = if i + 1 < td_count {
+                (tds.address() as u32 + ((i + 1) * mem::size_of::<Td>()) as u32) | 4
+            } else {
+                1
+            };
+
+            let toggle_bit = if toggle.get() { 1 << 19 } else { 0 };
+            toggle.set(!toggle.get());
+
+            tds.store(i,
+                      Td {
+                          link_ptr: link_ptr,
+                          ctrl_sts: 1 << 24 | 1 << 23,
+                          token: (cmp::max(packet_len, 1) as u32 - 1) << 21 |
+                                 (endpoint as u32) << 15 |
+                                 (address as u32) << 8 | toggle_bit | pid,
+                          buffer: buffer.offset(offset as isize) as u32,
+                      });
+
+            offset += packet_len;
         }
 
-        loop {
-            if out_td.load(0).ctrl_sts & (1 << 23) == 0 {
-                break;
-            }
-        }
+        let mut queue_head = Memory::<Qh>::new(1).unwrap();
+        queue_head.store(0,
+                         Qh {
+                             head_ptr: 1,
+                             element_ptr: tds.address() as u32,
+                         });
+
+        let last_td = (tds.address() + (td_count - 1) * mem::size_of::<Td>()) as *mut Td;
+        wait_for_transfer(self.transfers,
+                          self.async_qh,
+                          queue_head.address() as u32,
+                          last_td);
 
-        ptr::write(frame_list.offset(frame as isize), 1);
+        let mut actual = 0;
+        for i in 0..td_count {
+            let td = (tds.address() + i * mem::size_of::<Td>()) as *const Td;
+            actual += ((volatile_load(td).ctrl_sts & 0x7FF) + 1) & 0x7FF;
+        }
+        actual
     }
+}
 
-    unsafe fn device(&self, frame_list: *mut u32, address: u8) {
-        self.set_address(frame_list, address);
-
-        let desc_dev: *mut DeviceDescriptor = memory::alloc_type();
-        ptr::write(desc_dev, DeviceDescriptor::default());
-        self.descriptor(frame_list,
-                        address,
-                        DESC_DEV,
-                        0,
-                        desc_dev as u32,
-                        mem::size_of_val(&*desc_dev) as u32);
-        debugln!("{:#?}", *desc_dev);
-
-        for configuration in 0..(*desc_dev).configurations {
-            let desc_cfg_len = 1023;
-            let desc_cfg_buf = memory::alloc(desc_cfg_len) as *mut u8;
-            for i in 0..desc_cfg_len as isize {
-                ptr::write(desc_cfg_buf.offset(i), 0);
-            }
-            self.descriptor(frame_list,
-                            address,
-                            DESC_CFG,
-                            configuration,
-                            desc_cfg_buf as u32,
-                            desc_cfg_len as u32);
-
-            let desc_cfg = ptr::read(desc_cfg_buf as *const ConfigDescriptor);
-            debugln!("{:#?}", desc_cfg);
-
-            let mut hid = false;
-
-            let mut i = desc_cfg.length as isize;
-            while i < desc_cfg.total_length as isize {
-                let length = ptr::read(desc_cfg_buf.offset(i));
-                let descriptor_type = ptr::read(desc_cfg_buf.offset(i + 1));
-                match descriptor_type {
-                    DESC_INT => {
-                        let desc_int = ptr::read(desc_cfg_buf.offset(i) as *const InterfaceDescriptor);
-                        debugln!("{:#?}", desc_int);
-                    }
-                    DESC_END => {
-                        let desc_end = ptr::read(desc_cfg_buf.offset(i) as *const EndpointDescriptor);
-                        debugln!("{:#?}", desc_end);
-
-                        let endpoint = desc_end.address & 0xF;
-                        let in_len = desc_end.max_packet_size as usize;
-
-                        let base = self.base as u16;
-                        let frnum = base + 0x6;
-
-                        if hid {
-                            Context::spawn("kuhci_hid".to_string(), box move || {
-                                debugln!("Starting HID driver");
-
-                                let in_ptr = memory::alloc(in_len) as *mut u8;
-                                let in_td: *mut Td = memory::alloc_type();
-
-                                loop {
-                                    for i in 0..in_len as isize {
-                                        volatile_store(in_ptr.offset(i), 0);
-                                    }
-
-                                    ptr::write(in_td,
-                                               Td {
-                                                   link_ptr: 1,
-                                                   ctrl_sts: 1 << 25 | 1 << 23,
-                                                   token: (in_len as u32 - 1) << 21 |
-                                                          (endpoint as u32) << 15 |
-                                                          (address as u32) << 8 |
-                                                          0x69,
-                                                   buffer: in_ptr as u32,
-                                               });
-
-                                    let frame = {
-                                        let _intex = Intex::static_lock();
-
-                                        let frame = (inw(frnum) + 2) & 0x3FF;
-                                        volatile_store(frame_list.offset(frame as isize), in_td as u32);
-                                        frame
-                                    };
-
-                                    loop {
-                                        {
-                                            let ctrl_sts = volatile_load(in_td).ctrl_sts;
-                                            if ctrl_sts & (1 << 23) == 0 {
-                                                break;
-                                            }
-                                        }
-
-                                        context::context_switch(false);
-                                    }
-
-                                    volatile_store(frame_list.offset(frame as isize), 1);
-
-                                    if volatile_load(in_td).ctrl_sts & 0x7FF > 0 {
-                                       let buttons = ptr::read(in_ptr.offset(0) as *const u8) as usize;
-                                       let x = ptr::read(in_ptr.offset(1) as *const u16) as usize;
-                                       let y = ptr::read(in_ptr.offset(3) as *const u16) as usize;
-
-                                       let mode_info = &*VBEMODEINFO;
-                                       let mouse_x = (x * mode_info.xresolution as usize) / 32768;
-                                       let mouse_y = (y * mode_info.yresolution as usize) / 32768;
-
-                                       let mouse_event = MouseEvent {
-                                           x: cmp::max(0, cmp::min(mode_info.xresolution as i32 - 1, mouse_x as i32)),
-                                           y: cmp::max(0, cmp::min(mode_info.yresolution as i32 - 1, mouse_y as i32)),
-                                           left_button: buttons & 1 == 1,
-                                           middle_button: buttons & 4 == 4,
-                                           right_button: buttons & 2 == 2,
-                                       };
-                                       ::env().events.lock().push_back(mouse_event.to_event());
-                                    }
-
-                                    Duration::new(0, 10 * time::NANOS_PER_MILLI).sleep();
-                                }
-
-                            // memory::unalloc(in_td as usize);
-                            });
-                        }
-                    }
-                    DESC_HID => {
-                        let desc_hid = &*(desc_cfg_buf.offset(i) as *const HIDDescriptor);
-                        debugln!("{:#?}", desc_hid);
-                        hid = true;
-                    }
-                    _ => {
-                        debug::d("Unknown Descriptor Length ");
-                        debug::dd(length as usize);
-                        debug::d(" Type ");
-                        debug::dh(descriptor_type as usize);
-                        debug::dl();
-                    }
-                }
-                i += length as isize;
-            }
+impl Uhci {
+    pub unsafe fn new(mut pci: PciConfig) -> Box<Self> {
+        pci.flag(4, 4, true); // Bus mastering
 
-            memory::unalloc(desc_cfg_buf as usize);
+        let transfers: *mut Vec<UhciTransfer> = memory::alloc_type();
+        ptr::write(transfers, Vec::new());
+
+        // Every frame_list slot points at this one queue head for the whole
+        // lifetime of the controller; individual transfers are spliced into
+        // (and out of) the chain hanging off it by relink_schedule, instead
+        // of ever writing a frame_list slot directly.
+        let async_qh: *mut Qh = memory::alloc_type();
+        ptr::write(async_qh,
+                   Qh {
+                       head_ptr: 1,
+                       element_ptr: 1,
+                   });
+
+        let frame_list = memory::alloc(1024 * 4) as *mut u32;
+        for i in 0..1024 {
+            ptr::write(frame_list.offset(i), async_qh as u32 | 2);
         }
 
-        memory::unalloc(desc_dev as usize);
+        let module = box Uhci {
+            base: pci.read(0x20) as usize & 0xFFFFFFF0,
+            irq: pci.read(0x3C) as u8 & 0xF,
+            frame_list: frame_list,
+            async_qh: async_qh,
+            transfers: transfers,
+        };
+
+        module.init();
+
+        return module;
     }
 
     pub unsafe fn init(&self) {
@@ -460,6 +404,9 @@ impl Uhci {
 
         debug::d(" INTR ");
         debug::dh(inw(usbintr) as usize);
+        outw(usbintr, 1 << 2 | 1 << 0); // IOC and Timeout/CRC interrupts
+        debug::d(" to ");
+        debug::dh(inw(usbintr) as usize);
 
         debug::d(" FRNUM ");
         debug::dh(inw(frnum) as usize);
@@ -469,11 +416,7 @@ impl Uhci {
 
         debug::d(" FLBASEADD ");
         debug::dh(ind(flbaseadd) as usize);
-        let frame_list = memory::alloc(1024 * 4) as *mut u32;
-        for i in 0..1024 {
-            ptr::write(frame_list.offset(i), 1);
-        }
-        outd(flbaseadd, frame_list as u32);
+        outd(flbaseadd, self.frame_list as u32);
         debug::d(" to ");
         debug::dh(ind(flbaseadd) as usize);
 
@@ -485,6 +428,12 @@ impl Uhci {
 
         debug::dl();
 
+        // Shared by both root ports (and, transitively, every hub spawned
+        // from one of them) so no two devices are ever assigned the same
+        // address.
+        let next_address: *mut Cell<u8> = memory::alloc_type();
+        ptr::write(next_address, Cell::new(1));
+
         {
             debug::d(" PORTSC1 ");
             debug::dh(inw(portsc1) as usize);
@@ -508,7 +457,7 @@ impl Uhci {
                 debug::dh(inw(portsc1) as usize);
                 debug::dl();
 
-                self.device(frame_list, 1);
+                enumerate(self, next_address);
             }
         }
 
@@ -535,7 +484,7 @@ impl Uhci {
                 debug::dh(inw(portsc2) as usize);
                 debug::dl();
 
-                self.device(frame_list, 2);
+                enumerate(self, next_address);
             }
         }
     }